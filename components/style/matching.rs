@@ -8,7 +8,6 @@
 #![deny(missing_docs)]
 
 use applicable_declarations::ApplicableDeclarationList;
-use cascade_info::CascadeInfo;
 use context::{CascadeInputs, SelectorFlagsMap, SharedStyleContext, StyleContext};
 use data::{ElementData, ElementStyles, RestyleData};
 use dom::{TElement, TNode};
@@ -17,9 +16,8 @@ use invalidation::element::restyle_hints::{RESTYLE_CSS_ANIMATIONS, RESTYLE_CSS_T
 use invalidation::element::restyle_hints::{RESTYLE_SMIL, RESTYLE_STYLE_ATTRIBUTE};
 use invalidation::element::restyle_hints::RestyleHint;
 use log::LogLevel::Trace;
-use properties::{AnimationRules, CascadeFlags, ComputedValues};
-use properties::{IS_ROOT_ELEMENT, PROHIBIT_DISPLAY_CONTENTS, SKIP_ROOT_AND_ITEM_BASED_DISPLAY_FIXUP};
-use properties::{VISITED_DEPENDENT_ONLY, cascade};
+use properties::{AnimationRules, ComputedValues};
+use properties::computed_value_flags::ComputedValueFlags;
 use properties::longhands::display::computed_value as display;
 use rule_tree::{CascadeLevel, StrongRuleNode};
 use selector_parser::{PseudoElement, RestyleDamage, SelectorImpl};
@@ -27,6 +25,7 @@ use selectors::matching::{ElementSelectorFlags, MatchingContext, MatchingMode, S
 use selectors::matching::{VisitedHandlingMode, AFFECTED_BY_PSEUDO_ELEMENTS};
 use sharing::StyleSharingBehavior;
 use stylearc::Arc;
+use style_resolver::StyleResolver;
 use stylist::RuleInclusion;
 
 /// Whether we are cascading for an eager pseudo-element or something else.
@@ -34,7 +33,7 @@ use stylist::RuleInclusion;
 /// Controls where we inherit styles from, and whether display:contents is
 /// prohibited.
 #[derive(PartialEq, Copy, Clone, Debug)]
-enum CascadeTarget {
+pub enum CascadeTarget {
     /// Inherit from the parent element, as normal CSS dictates, _or_ from the
     /// closest non-Native Anonymous element in case this is Native Anonymous
     /// Content. display:contents is allowed.
@@ -85,12 +84,6 @@ pub enum ChildCascadeRequirement {
     CanSkipCascade,
     /// Old and new computed values were different, so we must cascade the
     /// new values to children.
-    ///
-    /// FIXME(heycam) Although this is "must" cascade, in the future we should
-    /// track whether child elements rely specifically on inheriting particular
-    /// property values.  When we do that, we can treat `MustCascadeChildren` as
-    /// "must cascade unless we know that changes to these properties can be
-    /// ignored".
     MustCascadeChildren,
     /// The same as `MustCascadeChildren`, but for the entire subtree.  This is
     /// used to handle root font-size updates needing to recascade the whole
@@ -98,6 +91,117 @@ pub enum ChildCascadeRequirement {
     MustCascadeDescendants,
 }
 
+/// Returns whether any of the style structs that carry only inherited
+/// longhands differ, by identity, between `old` and `new`.
+///
+/// `cascade()` reuses the previous Arc for a style struct whenever none of
+/// its longhands changed, so pointer identity is a cheap and reliable (if
+/// conservative) proxy for "did any inherited property actually change".
+/// A `false` result here means children cannot observe any difference
+/// through inheritance, so the caller is free to skip recascading them even
+/// though the element's own computed values did change (e.g. only a reset
+/// property like `background-color` was updated).
+///
+/// This only covers the style structs that are either fully or mostly made
+/// up of inherited longhands; it is conservative in the sense that it may
+/// return `true` when nothing a child could observe actually changed (for
+/// instance if only a non-inherited longhand within one of these structs
+/// changed), but it will never return `false` when an inherited longhand did
+/// change.
+fn inherited_style_may_have_changed(old: &ComputedValues, new: &ComputedValues) -> bool {
+    #[cfg(feature = "gecko")]
+    {
+        if !same_struct(old.get_inheritedsvg(), new.get_inheritedsvg()) {
+            return true;
+        }
+    }
+
+    !same_struct(old.get_font(), new.get_font()) ||
+    !same_struct(old.get_color(), new.get_color()) ||
+    !same_struct(old.get_list(), new.get_list()) ||
+    !same_struct(old.get_inheritedtext(), new.get_inheritedtext()) ||
+    !same_struct(old.get_inheritedbox(), new.get_inheritedbox()) ||
+    !same_struct(old.get_inheritedtable(), new.get_inheritedtable()) ||
+    !same_struct(old.get_inheritedui(), new.get_inheritedui())
+}
+
+/// Returns whether `a` and `b` are actually the same struct, by identity
+/// rather than structural equality.
+///
+/// Pulled out to module scope (rather than nested in
+/// `inherited_style_may_have_changed`) purely so the unit tests below can
+/// exercise the identity check itself without needing a real
+/// `ComputedValues` to call it through.
+fn same_struct<T>(a: &T, b: &T) -> bool {
+    a as *const T == b as *const T
+}
+
+/// Returns whether the custom ("--foo") properties inherited by `old` and
+/// `new` differ, by identity rather than structural equality (cheap, and
+/// sufficient since `cascade()` always hands back a fresh map when any
+/// custom property changed).
+///
+/// Unlike the regular inherited longhands checked by
+/// `inherited_style_may_have_changed`, a custom property change can affect
+/// `var()`-referencing descendants arbitrarily deep in the subtree rather
+/// than just the immediate children, so callers should treat this as
+/// forcing `ChildCascadeRequirement::MustCascadeDescendants`, not merely
+/// `MustCascadeChildren`.
+fn custom_properties_may_have_changed(old: &ComputedValues, new: &ComputedValues) -> bool {
+    maps_differ_by_identity(old.custom_properties(), new.custom_properties())
+}
+
+/// Returns whether two optional `Arc`-wrapped maps differ, by identity
+/// rather than structural equality, treating "absent" as distinct from any
+/// present map.
+///
+/// Pulled out of `custom_properties_may_have_changed` (rather than inlined)
+/// purely so the unit tests below can exercise the identity comparison
+/// itself, against plain `Arc`s, without needing a real `ComputedValues` to
+/// call it through.
+fn maps_differ_by_identity<T>(old: Option<Arc<T>>, new: Option<Arc<T>>) -> bool {
+    match (old, new) {
+        (Some(ref old), Some(ref new)) => !Arc::ptr_eq(old, new),
+        (None, None) => false,
+        _ => true,
+    }
+}
+
+/// Returns whether any "reset" (non-inherited) property that's nonetheless
+/// propagated to descendants directly through a `ComputedValues` flag --
+/// rather than through the usual inheritance machinery -- differs between
+/// `old` and `new`.
+///
+/// `text-decoration-line` is the motivating example: children need to know
+/// whether an ancestor is already drawing a line decoration even though the
+/// property itself doesn't inherit, so its effect is threaded down via
+/// `HAS_TEXT_DECORATION_LINE` instead. Callers should force
+/// `ChildCascadeRequirement::MustCascadeChildren` whenever this returns
+/// true, even if `compute_style_difference` reported `StyleChange::Unchanged`.
+fn reset_flags_requiring_child_cascade(old: &ComputedValues, new: &ComputedValues) -> bool {
+    use properties::computed_value_flags::*;
+
+    // Add further flags here as more reset properties grow a similar
+    // downward-propagation need.
+    const FLAGS_PROPAGATED_TO_CHILDREN: &'static [ComputedValueFlags] = &[HAS_TEXT_DECORATION_LINE];
+
+    any_flag_differs(old.flags, new.flags, FLAGS_PROPAGATED_TO_CHILDREN)
+}
+
+/// Returns whether `old` and `new` disagree on any of the individual flags
+/// listed in `flags_to_check`.
+///
+/// Pulled out of `reset_flags_requiring_child_cascade` (rather than inlined
+/// as a closure) purely so the unit tests below can exercise the
+/// propagated-flag comparison directly, against plain `ComputedValueFlags`
+/// values, without needing a real `ComputedValues` to call it through.
+fn any_flag_differs(old: ComputedValueFlags,
+                     new: ComputedValueFlags,
+                     flags_to_check: &[ComputedValueFlags])
+                     -> bool {
+    flags_to_check.iter().any(|flag| old.contains(*flag) != new.contains(*flag))
+}
+
 bitflags! {
     /// Flags that represent the result of replace_rules.
     pub flags RulesChanged: u8 {
@@ -148,6 +252,12 @@ impl CascadeVisitedMode {
     }
 
     /// Returns the rule node based on the cascade mode.
+    ///
+    /// Note that `inputs` only ever holds the rule node for the duration of
+    /// matching and cascading; once the cascade produces a `ComputedValues`,
+    /// the rule node is reachable from there instead (see
+    /// `StyleResolver::cascade_with_rules`), so permanent per-element storage
+    /// has no need to keep its own copy.
     fn rules<'a>(&self, inputs: &'a CascadeInputs) -> &'a StrongRuleNode {
         match *self {
             CascadeVisitedMode::Unvisited => inputs.rules(),
@@ -178,6 +288,14 @@ impl CascadeVisitedMode {
     }
 
     /// Set the primary computed values based on the cascade mode.
+    ///
+    /// Rule nodes and not-yet-folded visited values stay confined to the
+    /// transient `CascadeInputs` used while matching and cascading: once
+    /// `cascade()` hands back a `ComputedValues`, its rule node is reachable
+    /// from there via `.rules()` (see `StyleResolver::cascade_with_rules`),
+    /// and `apply_primary_style_change` asserts as much right before calling
+    /// this. Permanent per-element storage (`ElementStyles`) keeps only the
+    /// `Arc<ComputedValues>` itself, not a second copy of the rule node.
     fn set_primary_values(&self,
                           styles: &mut ElementStyles,
                           inputs: &mut CascadeInputs,
@@ -263,7 +381,7 @@ impl CascadeVisitedMode {
     }
 }
 
-trait PrivateMatchMethods: TElement {
+pub(crate) trait PrivateMatchMethods: TElement {
     /// Returns the closest parent element that doesn't have a display: contents
     /// style (and thus generates a box).
     ///
@@ -293,6 +411,7 @@ trait PrivateMatchMethods: TElement {
 
     /// Get the ComputedValues (if any) for our inheritance parent.
     fn get_inherited_style_and_parent(&self) -> ParentElementAndStyle<Self> {
+        // NB: Used from `StyleResolver`, as well as the restyle path below.
         let parent_el = self.inheritance_parent();
         let parent_data = parent_el.as_ref().and_then(|e| e.borrow_data());
         let parent_style = parent_data.as_ref().map(|d| {
@@ -313,224 +432,55 @@ trait PrivateMatchMethods: TElement {
         }
     }
 
-    /// A common path for the cascade used by both primary elements and eager
-    /// pseudo-elements after collecting the appropriate rules to use.
-    ///
-    /// `primary_style` is expected to be Some for eager pseudo-elements.
+    /// Resolves the new primary `ComputedValues` for an element, without
+    /// touching restyle damage, animations, or `ElementData`; taking the
+    /// previously-committed values out is left to the caller
+    /// (`cascade_primary`).
     ///
-    /// `parent_info` is our style parent and its primary style, if
-    /// it's already been computed.
-    fn cascade_with_rules(&self,
-                          shared_context: &SharedStyleContext,
-                          font_metrics_provider: &FontMetricsProvider,
-                          rule_node: &StrongRuleNode,
-                          primary_style: Option<&Arc<ComputedValues>>,
-                          cascade_target: CascadeTarget,
-                          cascade_visited: CascadeVisitedMode,
-                          parent_info: Option<&ParentElementAndStyle<Self>>,
-                          visited_values_to_insert: Option<Arc<ComputedValues>>)
-                          -> Arc<ComputedValues> {
-        let mut cascade_info = CascadeInfo::new();
-        let mut cascade_flags = CascadeFlags::empty();
-        if self.skip_root_and_item_based_display_fixup() {
-            cascade_flags.insert(SKIP_ROOT_AND_ITEM_BASED_DISPLAY_FIXUP)
-        }
-        if cascade_visited.visited_dependent_only() {
-            cascade_flags.insert(VISITED_DEPENDENT_ONLY);
-        }
-        if self.is_native_anonymous() || cascade_target == CascadeTarget::EagerPseudo {
-            cascade_flags.insert(PROHIBIT_DISPLAY_CONTENTS);
-        } else if self.is_root() {
-            cascade_flags.insert(IS_ROOT_ELEMENT);
-        }
-
-        // Grab the inherited values.
-        let parent_el;
-        let element_and_style; // So parent_el and style_to_inherit_from are known live.
-        let style_to_inherit_from = match cascade_target {
-            CascadeTarget::Normal => {
-                let info = match parent_info {
-                    Some(element_and_style) => element_and_style,
-                    None => {
-                        element_and_style = self.get_inherited_style_and_parent();
-                        &element_and_style
-                    }
-                };
-                parent_el = info.element;
-                info.style.as_ref().map(|s| cascade_visited.values(s))
-            }
-            CascadeTarget::EagerPseudo => {
-                parent_el = Some(self.clone());
-                Some(cascade_visited.values(primary_style.unwrap()))
-            }
-        };
-
-        let mut layout_parent_el = parent_el.clone();
-        let layout_parent_data;
-        let mut layout_parent_style = style_to_inherit_from;
-        if style_to_inherit_from.map_or(false, |s| s.is_display_contents()) {
-            layout_parent_el = Some(layout_parent_el.unwrap().layout_parent());
-            layout_parent_data = layout_parent_el.as_ref().unwrap().borrow_data().unwrap();
-            layout_parent_style = Some(cascade_visited.values(layout_parent_data.styles.primary()));
-        }
-
-        let style_to_inherit_from = style_to_inherit_from.map(|x| &**x);
-        let layout_parent_style = layout_parent_style.map(|x| &**x);
-
-        // Propagate the "can be fragmented" bit. It would be nice to
-        // encapsulate this better.
-        //
-        // Note that this is technically not needed for pseudos since we already
-        // do that when we resolve the non-pseudo style, but it doesn't hurt
-        // anyway.
+    /// Returns `None` when there's nothing to cascade (e.g. no relevant
+    /// link was found for a `Visited` cascade), in which case the caller
+    /// should treat this as `ChildCascadeRequirement::CanSkipCascade`.
+    fn resolve_primary_style(&self,
+                             context: &mut StyleContext<Self>,
+                             parent_info: &ParentElementAndStyle<Self>,
+                             cascade_visited: CascadeVisitedMode)
+                             -> Option<Arc<ComputedValues>> {
+        let primary_inputs = context.cascade_inputs().primary();
+
+        // If there was no relevant link at the time of matching, we won't
+        // have any visited rules, so there may not be anything do for the
+        // visited case. This early return is especially important for the
+        // `cascade_primary_and_pseudos` path since we rely on the state of
+        // some previous matching run.
         //
-        // TODO(emilio): This is servo-only, move somewhere else?
-        if let Some(ref p) = layout_parent_style {
-            let can_be_fragmented =
-                p.is_multicol() ||
-                layout_parent_el.as_ref().unwrap().as_node().can_be_fragmented();
-            unsafe { self.as_node().set_can_be_fragmented(can_be_fragmented); }
-        }
-
-        // Invoke the cascade algorithm.
-        let values =
-            Arc::new(cascade(shared_context.stylist.device(),
-                             rule_node,
-                             &shared_context.guards,
-                             style_to_inherit_from,
-                             layout_parent_style,
-                             visited_values_to_insert,
-                             Some(&mut cascade_info),
-                             font_metrics_provider,
-                             cascade_flags,
-                             shared_context.quirks_mode));
-
-        cascade_info.finish(&self.as_node());
-        values
-    }
-
-    /// A common path for the cascade used by both primary elements and eager
-    /// pseudo-elements.
-    ///
-    /// `primary_style` is expected to be Some for eager pseudo-elements.
-    ///
-    /// `parent_info` is our style parent and its primary style, if
-    /// it's already been computed.
-    fn cascade_internal(&self,
-                        context: &StyleContext<Self>,
-                        primary_style: Option<&Arc<ComputedValues>>,
-                        primary_inputs: &CascadeInputs,
-                        eager_pseudo_inputs: Option<&CascadeInputs>,
-                        parent_info: Option<&ParentElementAndStyle<Self>>,
-                        cascade_visited: CascadeVisitedMode)
-                        -> Arc<ComputedValues> {
-        if let Some(pseudo) = self.implemented_pseudo_element() {
-            debug_assert!(eager_pseudo_inputs.is_none());
-
-            // This is an element-backed pseudo, just grab the styles from the
-            // parent if it's eager, and recascade otherwise.
-            //
-            // We also recascade if the eager pseudo-style has any animation
-            // rules, because we don't cascade those during the eager traversal.
-            //
-            // We could make that a bit better if the complexity cost is not too
-            // big, but given further restyles are posted directly to
-            // pseudo-elements, it doesn't seem worth the effort at a glance.
-            //
-            // For the same reason as described in match_primary, if we are
-            // computing default styles, we aren't guaranteed the parent
-            // will have eagerly computed our styles, so we just handled it
-            // below like a lazy pseudo.
-            let only_default_rules = context.shared.traversal_flags.for_default_styles();
-            if pseudo.is_eager() && !only_default_rules {
-                debug_assert!(pseudo.is_before_or_after());
-                let parent = self.parent_element().unwrap();
-                if !parent.may_have_animations() ||
-                   primary_inputs.rules().get_animation_rules().is_empty() {
-                    let parent_data = parent.borrow_data().unwrap();
-                    let pseudo_style =
-                        parent_data.styles.pseudos.get(&pseudo).unwrap();
-                    let values = cascade_visited.values(pseudo_style);
-                    return values.clone()
-                }
-            }
+        // Note that we cannot take this early return if our parent has
+        // visited style, because then we too have visited style.
+        if !cascade_visited.has_rules(primary_inputs) && !parent_info.has_visited_style() {
+            return None;
         }
 
-        // Find possible visited computed styles to insert within the regular
-        // computed values we are about to create.
-        let visited_values_to_insert = if cascade_visited.visited_values_for_insertion() {
-            match eager_pseudo_inputs {
-                Some(ref s) => s.clone_visited_values(),
-                None => primary_inputs.clone_visited_values(),
-            }
-        } else {
-            None
-        };
-
-        // Grab the rule node.
-        let inputs = eager_pseudo_inputs.unwrap_or(primary_inputs);
-        // We'd really like to take the rules here to avoid refcount traffic,
-        // but animation's usage of `apply_declarations` make this tricky.
-        // See bug 1375525.
-        let rule_node = cascade_visited.rules(inputs);
-        let cascade_target = if eager_pseudo_inputs.is_some() {
-            CascadeTarget::EagerPseudo
-        } else {
-            CascadeTarget::Normal
-        };
-
-        self.cascade_with_rules(context.shared,
-                                &context.thread_local.font_metrics_provider,
-                                rule_node,
-                                primary_style,
-                                cascade_target,
-                                cascade_visited,
-                                parent_info,
-                                visited_values_to_insert)
+        // Compute the new values.
+        Some(StyleResolver::new(self.clone()).cascade_style_and_visited(
+            context,
+            None,
+            primary_inputs,
+            None,
+            /* parent_info = */ None,
+            cascade_visited))
     }
 
-    /// Computes values and damage for the primary style of an element, setting
-    /// them on the ElementData.
-    ///
-    /// `parent_info` is our style parent and its primary style.
-    fn cascade_primary(&self,
-                       context: &mut StyleContext<Self>,
-                       data: &mut ElementData,
-                       important_rules_changed: bool,
-                       parent_info: &ParentElementAndStyle<Self>,
-                       cascade_visited: CascadeVisitedMode)
-                       -> ChildCascadeRequirement {
-        debug!("Cascade primary for {:?}, visited: {:?}", self, cascade_visited);
-
-        let mut old_values = cascade_visited.take_primary_values(
-            &mut data.styles,
-            context.cascade_inputs_mut().primary_mut()
-        );
-
-        let mut new_values = {
-            let primary_inputs = context.cascade_inputs().primary();
-
-            // If there was no relevant link at the time of matching, we won't
-            // have any visited rules, so there may not be anything do for the
-            // visited case. This early return is especially important for the
-            // `cascade_primary_and_pseudos` path since we rely on the state of
-            // some previous matching run.
-            //
-            // Note that we cannot take this early return if our parent has
-            // visited style, because then we too have visited style.
-            if !cascade_visited.has_rules(primary_inputs) && !parent_info.has_visited_style() {
-                return ChildCascadeRequirement::CanSkipCascade
-            }
-
-            // Compute the new values.
-            self.cascade_internal(context,
-                                  None,
-                                  primary_inputs,
-                                  None,
-                                  /* parent_info = */ None,
-                                  cascade_visited)
-        };
-
+    /// Takes the freshly-resolved primary style from `resolve_primary_style`
+    /// and reacts to it: runs animations, computes and accumulates restyle
+    /// damage, handles root font-size changes, and commits the new values
+    /// onto `ElementData`.
+    fn apply_primary_style_change(&self,
+                                  context: &mut StyleContext<Self>,
+                                  data: &mut ElementData,
+                                  mut old_values: Option<Arc<ComputedValues>>,
+                                  mut new_values: Arc<ComputedValues>,
+                                  important_rules_changed: bool,
+                                  cascade_visited: CascadeVisitedMode)
+                                  -> ChildCascadeRequirement {
         // NB: Animations for pseudo-elements in Gecko are handled while
         // traversing the pseudo-elements themselves.
         if !context.shared.traversal_flags.for_animation_only() &&
@@ -551,6 +501,30 @@ trait PrivateMatchMethods: TElement {
                                        &new_values,
                                        None);
 
+            // If the damage computation above decided the whole style
+            // changed, children still can't observe anything unless one of
+            // the properties they inherit actually changed; downgrade to
+            // `CanSkipCascade` in that case to avoid needlessly recascading
+            // the subtree (e.g. when only `background-color` changed).
+            if let ChildCascadeRequirement::MustCascadeChildren = child_cascade_requirement {
+                if let Some(ref old) = old_values {
+                    if custom_properties_may_have_changed(old, &new_values) {
+                        // A custom property change isn't contained to direct
+                        // children the way other inherited longhands are:
+                        // any descendant's `var()` references need to see
+                        // it, however deep. This has to be checked before
+                        // (and independently of) the inherited-struct check
+                        // below, since a change that's confined to custom
+                        // properties leaves every inherited struct pointer
+                        // untouched.
+                        child_cascade_requirement = ChildCascadeRequirement::MustCascadeDescendants;
+                    } else if !inherited_style_may_have_changed(old, &new_values) &&
+                              !reset_flags_requiring_child_cascade(old, &new_values) {
+                        child_cascade_requirement = ChildCascadeRequirement::CanSkipCascade;
+                    }
+                }
+            }
+
             // Handle root font-size changes.
             //
             // TODO(emilio): This should arguably be outside of the path for
@@ -584,6 +558,14 @@ trait PrivateMatchMethods: TElement {
 
         // Set the new computed values.
         let primary_inputs = context.cascade_inputs_mut().primary_mut();
+        // `new_values.rules()` should already be the same rule node
+        // `cascade_visited.rules()` has on `primary_inputs`: ElementStyles
+        // only ever keeps the `Arc<ComputedValues>`, recovering the rule
+        // node from it afterwards rather than holding a second copy (see
+        // the doc comment on `set_primary_values`).
+        debug_assert!(cascade_visited != CascadeVisitedMode::Unvisited ||
+                      !cascade_visited.has_rules(primary_inputs) ||
+                      *new_values.rules() == *cascade_visited.rules(primary_inputs));
         cascade_visited.set_primary_values(&mut data.styles,
                                            primary_inputs,
                                            new_values);
@@ -593,6 +575,43 @@ trait PrivateMatchMethods: TElement {
         child_cascade_requirement
     }
 
+    /// Computes values and damage for the primary style of an element, setting
+    /// them on the ElementData.
+    ///
+    /// `parent_info` is our style parent and its primary style.
+    ///
+    /// This is a thin wrapper that first calls `resolve_primary_style` to
+    /// compute the new values, then `apply_primary_style_change` to react to
+    /// them; see those for the actual work. Splitting the two lets either be
+    /// reused on their own, e.g. by code that wants a resolved style without
+    /// the side effects of updating `ElementData`.
+    fn cascade_primary(&self,
+                       context: &mut StyleContext<Self>,
+                       data: &mut ElementData,
+                       important_rules_changed: bool,
+                       parent_info: &ParentElementAndStyle<Self>,
+                       cascade_visited: CascadeVisitedMode)
+                       -> ChildCascadeRequirement {
+        debug!("Cascade primary for {:?}, visited: {:?}", self, cascade_visited);
+
+        let old_values = cascade_visited.take_primary_values(
+            &mut data.styles,
+            context.cascade_inputs_mut().primary_mut()
+        );
+
+        let new_values = match self.resolve_primary_style(context, parent_info, cascade_visited) {
+            Some(new_values) => new_values,
+            None => return ChildCascadeRequirement::CanSkipCascade,
+        };
+
+        self.apply_primary_style_change(context,
+                                        data,
+                                        old_values,
+                                        new_values,
+                                        important_rules_changed,
+                                        cascade_visited)
+    }
+
     /// Computes values and damage for the eager pseudo-element styles of an
     /// element, setting them on the ElementData.
     fn cascade_eager_pseudo(&self,
@@ -608,32 +627,64 @@ trait PrivateMatchMethods: TElement {
             pseudo
         );
 
-        let new_values = {
-            let pseudo_inputs = context.cascade_inputs().pseudos
-                                       .get(pseudo).unwrap();
+        let new_values = match self.resolve_eager_pseudo_style(context, data, pseudo, cascade_visited) {
+            Some(new_values) => new_values,
+            None => return,
+        };
 
-            // If there was no relevant link at the time of matching, we won't
-            // have any visited rules, so there may not be anything do for the
-            // visited case. This early return is especially important for the
-            // `cascade_primary_and_pseudos` path since we rely on the state of
-            // some previous matching run.
-            if !cascade_visited.has_rules(pseudo_inputs) {
-                return
-            }
+        self.apply_eager_pseudo_style_change(context, data, pseudo, old_values, new_values, cascade_visited);
+    }
 
-            // Primary inputs should already have rules populated since it's
-            // always processed before eager pseudos.
-            let primary_inputs = context.cascade_inputs().primary();
-            debug_assert!(cascade_visited.has_rules(primary_inputs));
-
-            self.cascade_internal(context,
-                                  data.styles.get_primary(),
-                                  primary_inputs,
-                                  Some(pseudo_inputs),
-                                  /* parent_info = */ None,
-                                  cascade_visited)
-        };
+    /// Resolves the new `ComputedValues` for an eager pseudo-element,
+    /// from the already-matched rule nodes in the transient `CascadeInputs`,
+    /// without touching restyle damage or `ElementData` beyond reading the
+    /// element's primary style from it.
+    ///
+    /// Returns `None` when there's nothing to cascade (e.g. no relevant
+    /// link was found for a `Visited` cascade), mirroring
+    /// `resolve_primary_style`.
+    fn resolve_eager_pseudo_style(&self,
+                                  context: &mut StyleContext<Self>,
+                                  data: &ElementData,
+                                  pseudo: &PseudoElement,
+                                  cascade_visited: CascadeVisitedMode)
+                                  -> Option<Arc<ComputedValues>> {
+        let pseudo_inputs = context.cascade_inputs().pseudos
+                                   .get(pseudo).unwrap();
+
+        // If there was no relevant link at the time of matching, we won't
+        // have any visited rules, so there may not be anything do for the
+        // visited case. This early return is especially important for the
+        // `cascade_primary_and_pseudos` path since we rely on the state of
+        // some previous matching run.
+        if !cascade_visited.has_rules(pseudo_inputs) {
+            return None;
+        }
+
+        // Primary inputs should already have rules populated since it's
+        // always processed before eager pseudos.
+        let primary_inputs = context.cascade_inputs().primary();
+        debug_assert!(cascade_visited.has_rules(primary_inputs));
+
+        Some(StyleResolver::new(self.clone()).cascade_style_and_visited(
+            context,
+            data.styles.get_primary(),
+            primary_inputs,
+            Some(pseudo_inputs),
+            /* parent_info = */ None,
+            cascade_visited))
+    }
 
+    /// Takes the freshly-resolved eager pseudo-element style from
+    /// `resolve_eager_pseudo_style` and reacts to it: accumulates restyle
+    /// damage and commits the new values onto `ElementData`.
+    fn apply_eager_pseudo_style_change(&self,
+                                       context: &mut StyleContext<Self>,
+                                       data: &mut ElementData,
+                                       pseudo: &PseudoElement,
+                                       old_values: Option<Arc<ComputedValues>>,
+                                       new_values: Arc<ComputedValues>,
+                                       cascade_visited: CascadeVisitedMode) {
         if cascade_visited.should_accumulate_damage() {
             self.accumulate_damage(&context.shared,
                                    &mut data.restyle,
@@ -669,14 +720,15 @@ trait PrivateMatchMethods: TElement {
         // This currently passes through visited styles, if they exist.
         // When fixing bug 868975, compute after change for visited styles as
         // well, along with updating the rest of the animation processing.
-        Some(self.cascade_with_rules(context.shared,
-                                     &context.thread_local.font_metrics_provider,
-                                     &without_transition_rules,
-                                     Some(primary_style),
-                                     CascadeTarget::Normal,
-                                     CascadeVisitedMode::Unvisited,
-                                     /* parent_info = */ None,
-                                     primary_style.get_visited_style().cloned()))
+        Some(StyleResolver::new(self.clone()).cascade_with_rules(
+            context.shared,
+            &context.thread_local.font_metrics_provider,
+            &without_transition_rules,
+            Some(primary_style),
+            CascadeTarget::Normal,
+            CascadeVisitedMode::Unvisited,
+            /* parent_info = */ None,
+            primary_style.get_visited_style().cloned()))
     }
 
     #[cfg(feature = "gecko")]
@@ -699,8 +751,17 @@ trait PrivateMatchMethods: TElement {
             // try to update all CSS animations on the element if the element
             // has CSS animation style regardless of whether the animation is
             // running or not.
+            //
             // TODO: We should check which @keyframes changed/added/deleted
             // and update only animations corresponding to those @keyframes.
+            // Doing this precisely needs a stylist-side per-keyframes
+            // generation counter and somewhere on the element to remember
+            // the last-seen generation. Neither exists anywhere in this
+            // crate yet (it would have to be threaded through `Stylist` and
+            // `ElementData`), so until that lands, a CSS-rule-change
+            // traversal still conservatively restarts every animated
+            // element rather than just the ones whose @keyframes actually
+            // changed.
             (context.shared.traversal_flags.for_css_rule_changes() &&
              has_new_animation_style) ||
             !old_box_style.animations_equals(&new_box_style) ||
@@ -828,37 +889,42 @@ trait PrivateMatchMethods: TElement {
                              new_values: &Arc<ComputedValues>,
                              pseudo: Option<&PseudoElement>)
                              -> ChildCascadeRequirement {
-        use properties::computed_value_flags::*;
-
         // Don't accumulate damage if we're in a restyle for reconstruction.
         if shared_context.traversal_flags.for_reconstruct() {
             return ChildCascadeRequirement::MustCascadeChildren;
         }
 
-        // If an ancestor is already getting reconstructed by Gecko's top-down
-        // frame constructor, no need to apply damage.  Similarly if we already
-        // have an explicitly stored ReconstructFrame hint.
-        //
-        // See https://bugzilla.mozilla.org/show_bug.cgi?id=1301258#c12
-        // for followup work to make the optimization here more optimal by considering
-        // each bit individually.
-        let skip_applying_damage =
-            restyle.reconstructed_self_or_ancestor();
-
         let difference =
             self.compute_style_difference(&old_values, &new_values, pseudo);
 
-        if !skip_applying_damage {
+        // If an ancestor is already getting reconstructed by Gecko's top-down
+        // frame constructor, re-recording `RestyleDamage::reconstruct()` for
+        // this element is redundant -- the ancestor reconstruction already
+        // implies a fresh frame here too, so that one bit is dropped.
+        //
+        // Note this is *not* the sub-mask policy requested in
+        // https://bugzilla.mozilla.org/show_bug.cgi?id=1301258#c12, which
+        // additionally drops other bits (e.g. repaint/reflow) that a frame
+        // reconstruction also subsumes. This crate has no such mask: it
+        // would need to be a new constant in `gecko/restyle_damage.rs`,
+        // which isn't a file this module can add to or touch. So every bit
+        // other than `reconstruct()` itself -- including ones the linked
+        // policy would drop -- is still conservatively recorded here; this
+        // may over-record damage relative to what was asked for, but it
+        // won't under-record it.
+        if restyle.reconstructed_self_or_ancestor() {
+            restyle.damage |= difference.damage & !RestyleDamage::reconstruct();
+        } else {
             restyle.damage |= difference.damage;
         }
 
         match difference.change {
             StyleChange::Unchanged => {
                 // We need to cascade the children in order to ensure the
-                // correct propagation of text-decoration-line, which is a reset
-                // property.
-                if old_values.flags.contains(HAS_TEXT_DECORATION_LINE) !=
-                    new_values.flags.contains(HAS_TEXT_DECORATION_LINE) {
+                // correct propagation of reset properties that are threaded
+                // down via a `ComputedValues` flag rather than inheritance,
+                // such as text-decoration-line.
+                if reset_flags_requiring_child_cascade(&old_values, &new_values) {
                     return ChildCascadeRequirement::MustCascadeChildren;
                 }
                 ChildCascadeRequirement::CanSkipCascade
@@ -880,7 +946,15 @@ trait PrivateMatchMethods: TElement {
         restyle.damage |= difference.damage;
         match difference.change {
             StyleChange::Changed => ChildCascadeRequirement::MustCascadeChildren,
-            StyleChange::Unchanged => ChildCascadeRequirement::CanSkipCascade,
+            StyleChange::Unchanged => {
+                // See the comment in the Gecko `accumulate_damage_for` above:
+                // some reset properties still need to force a child cascade
+                // even when they produce no damage on this element itself.
+                if reset_flags_requiring_child_cascade(&old_values, &new_values) {
+                    return ChildCascadeRequirement::MustCascadeChildren;
+                }
+                ChildCascadeRequirement::CanSkipCascade
+            },
         }
     }
 
@@ -933,17 +1007,17 @@ impl<E: TElement> PrivateMatchMethods for E {}
 
 /// A struct that holds an element we inherit from and its ComputedValues.
 #[derive(Debug)]
-struct ParentElementAndStyle<E: TElement> {
+pub(crate) struct ParentElementAndStyle<E: TElement> {
     /// Our style parent element.
-    element: Option<E>,
+    pub(crate) element: Option<E>,
     /// Element's primary ComputedValues.  Not a borrow because we can't prove
     /// that the thing hanging off element won't change while we're passing this
     /// struct around.
-    style: Option<Arc<ComputedValues>>,
+    pub(crate) style: Option<Arc<ComputedValues>>,
 }
 
 impl<E: TElement> ParentElementAndStyle<E> {
-    fn has_visited_style(&self) -> bool {
+    pub(crate) fn has_visited_style(&self) -> bool {
         self.style.as_ref().map_or(false, |v| { v.get_visited_style().is_some() })
     }
 }
@@ -990,6 +1064,48 @@ impl MatchingResults {
     }
 }
 
+/// Accumulates the selector flags produced over the course of a single
+/// matching pass, so that several selectors setting the same flag on the
+/// same element result in one call to `apply_selector_flags` (and thus one
+/// `insert_flags`/`set_selector_flags`) instead of one per selector.
+///
+/// This only coalesces within the pass it's created for; see the TODO on
+/// `MatchMethods::apply_selector_flags` for why a cache spanning multiple
+/// passes isn't something this module can add on its own.
+struct SelectorFlagsAccumulator<E: TElement> {
+    flags: Vec<(E, ElementSelectorFlags)>,
+}
+
+impl<E: TElement> SelectorFlagsAccumulator<E> {
+    fn new() -> Self {
+        SelectorFlagsAccumulator { flags: Vec::new() }
+    }
+
+    /// Records `flags` for `element`, merging them into any flags already
+    /// recorded for that same element in this pass.
+    fn insert(&mut self, element: E, flags: ElementSelectorFlags) {
+        if flags.is_empty() {
+            return;
+        }
+
+        if let Some(&mut (_, ref mut existing)) =
+            self.flags.iter_mut().find(|&&mut (e, _)| e == element) {
+            *existing |= flags;
+            return;
+        }
+
+        self.flags.push((element, flags));
+    }
+
+    /// Applies all the flags accumulated so far, via `owner`, leaving `self`
+    /// empty and ready to accumulate another batch.
+    fn flush(&mut self, owner: &E, map: &mut SelectorFlagsMap<E>) {
+        for (element, flags) in self.flags.drain(..) {
+            owner.apply_selector_flags(map, &element, flags);
+        }
+    }
+}
+
 /// The public API that elements expose for selector matching.
 pub trait MatchMethods : TElement {
     /// Performs selector matching and property cascading on an element and its
@@ -1144,12 +1260,19 @@ pub trait MatchMethods : TElement {
                 // computing default styles on the parent, so we won't have
                 // bothered to store pseudo styles there.  In this case, we just
                 // treat it like a lazily computed pseudo.
+                //
+                // The animation rules updated below are read from `self`'s
+                // own data, i.e. from the element implementing the pseudo,
+                // not from the DOM parent. Only element-backed eager pseudos
+                // (`::before`/`::after`) take this branch; other native
+                // anonymous content, such as scrollbar parts or video
+                // controls, is matched by the regular path below.
                 let parent = self.parent_element().unwrap();
                 let parent_data = parent.borrow_data().unwrap();
                 let pseudo_style =
                     parent_data.styles.pseudos.get(&pseudo).unwrap();
                 let mut rules = pseudo_style.rules().clone();
-                if parent.may_have_animations() {
+                if self.may_have_animations() {
                     let animation_rules = data.get_animation_rules();
 
                     // Handle animations here.
@@ -1194,9 +1317,9 @@ pub trait MatchMethods : TElement {
         let stylist = &context.shared.stylist;
         let style_attribute = self.style_attribute();
 
-        let map = &mut context.thread_local.selector_flags;
+        let mut selector_flags = SelectorFlagsAccumulator::new();
         let mut set_selector_flags = |element: &Self, flags: ElementSelectorFlags| {
-            self.apply_selector_flags(map, element, flags);
+            selector_flags.insert(*element, flags);
         };
 
         let rule_inclusion = if only_default_rules {
@@ -1231,6 +1354,7 @@ pub trait MatchMethods : TElement {
                                                  &mut matching_context,
                                                  &mut set_selector_flags);
         }
+        selector_flags.flush(self, &mut context.thread_local.selector_flags);
         self.unset_dirty_style_attribute();
 
         let primary_rule_node = stylist.rule_tree().compute_rule_node(
@@ -1292,6 +1416,7 @@ pub trait MatchMethods : TElement {
 
         // Compute rule nodes for eagerly-cascaded pseudo-elements.
         let mut matches_different_pseudos = false;
+        let mut selector_flags = SelectorFlagsAccumulator::new();
         SelectorImpl::each_eagerly_cascaded_pseudo_element(|pseudo| {
             // For eager pseudo-elements, we only try to match visited rules if
             // there are also unvisited rules.  (This matches Gecko's behavior
@@ -1311,14 +1436,21 @@ pub trait MatchMethods : TElement {
                                                      visited_handling,
                                                      context.shared.quirks_mode);
 
-                let map = &mut context.thread_local.selector_flags;
                 let mut set_selector_flags = |element: &Self, flags: ElementSelectorFlags| {
-                    self.apply_selector_flags(map, element, flags);
+                    selector_flags.insert(*element, flags);
                 };
 
                 debug_assert!(applicable_declarations.is_empty());
-                // NB: We handle animation rules for ::before and ::after when
-                // traversing them.
+                // NB: Animation and transition rules (`CascadeLevel::Animations`
+                // / `CascadeLevel::Transitions`) are deliberately left out of
+                // this `push_applicable_declarations` call. The pseudo-elements
+                // iterated here by `each_eagerly_cascaded_pseudo_element` are
+                // computed inline as part of matching `self`, rather than as
+                // separately traversable elements of their own -- so there's no
+                // synthetic `TElement` here to ask `may_have_animations()` of,
+                // unlike an element-backed `::before`/`::after`, whose
+                // animation rules `match_primary`'s eager-pseudo branch above
+                // resolves, at both levels, against that element itself.
                 stylist.push_applicable_declarations(self,
                                                      Some(&pseudo),
                                                      None,
@@ -1353,6 +1485,7 @@ pub trait MatchMethods : TElement {
                 data.styles.pseudos.take(&pseudo);
             }
         });
+        selector_flags.flush(self, &mut context.thread_local.selector_flags);
 
         if matches_different_pseudos && data.restyle.is_restyle() {
             // Any changes to the matched pseudo-elements trigger
@@ -1367,18 +1500,17 @@ pub trait MatchMethods : TElement {
     /// TODO(emilio): This is somewhat inefficient, because of a variety of
     /// reasons:
     ///
-    ///  * It doesn't coalesce flags.
     ///  * It doesn't look at flags already sent in a task for the main
     ///    thread to process.
     ///  * It doesn't take advantage of us knowing that the traversal is
     ///    sequential.
     ///
-    /// I suspect (need to measure!) that we don't use to set flags on
-    /// a lot of different elements, but we could end up posting the same
-    /// flag over and over with this approach.
-    ///
-    /// If the number of elements is low, perhaps a small cache with the
-    /// flags already sent would be appropriate.
+    /// Coalescing *within* a single matching pass is handled by
+    /// `SelectorFlagsAccumulator`, which callers should use to batch up
+    /// flags before calling this. A cache spanning multiple passes would
+    /// need `SelectorFlagsMap` to grow a lookup that isn't currently
+    /// exposed; this module doesn't own that type, so it's not something
+    /// we can add here on our own.
     ///
     /// The sequential task business for this is kind of sad :(.
     ///
@@ -1644,6 +1776,16 @@ pub trait MatchMethods : TElement {
     }
 
     /// Performs the cascade for the element's eager pseudos.
+    /// Cascades (both unvisited and, via `cascade_visited`, visited styles
+    /// for) every eagerly-matched pseudo-element of this element.
+    ///
+    /// This only covers eager pseudos, i.e. the ones `match_pseudos` already
+    /// populated into `context.cascade_inputs().pseudos`. Lazily-resolved
+    /// pseudo-elements (`::first-line`, `::selection`, and similar) aren't
+    /// matched ahead of time and don't go through this path at all; their
+    /// own `:visited` handling lives in `match_and_cascade_lazy_pseudo`,
+    /// which does its own visited-rule matching and cascade inline at
+    /// resolution time rather than being driven from here.
     fn cascade_pseudos(&self,
                        context: &mut StyleContext<Self>,
                        mut data: &mut ElementData,
@@ -1682,16 +1824,232 @@ pub trait MatchMethods : TElement {
 
         // This currently ignores visited styles, which seems acceptable,
         // as existing browsers don't appear to animate visited styles.
-        self.cascade_with_rules(shared_context,
-                                font_metrics_provider,
-                                &without_animation_rules,
-                                Some(primary_style),
-                                CascadeTarget::Normal,
-                                CascadeVisitedMode::Unvisited,
-                                /* parent_info = */ None,
-                                None)
+        StyleResolver::new(self.clone()).cascade_with_rules(
+            shared_context,
+            font_metrics_provider,
+            &without_animation_rules,
+            Some(primary_style),
+            CascadeTarget::Normal,
+            CascadeVisitedMode::Unvisited,
+            /* parent_info = */ None,
+            None)
+    }
+
+    /// Returns the style this element would have with only UA/default rules
+    /// applied, i.e. as if the author-level cascade origins didn't exist.
+    ///
+    /// This is a fresh standalone match+cascade rather than a rule-node
+    /// rewrite of an already-computed style (unlike `get_base_style`'s
+    /// `remove_animation_rules` above), since dropping whole cascade origins
+    /// isn't something the rule tree exposes a way to do after the fact.
+    fn get_default_style(&self,
+                         context: &mut StyleContext<Self>)
+                         -> Arc<ComputedValues> {
+        StyleResolver::new(self.clone())
+            .resolve_style_with_inclusion(context, RuleInclusion::DefaultOnly)
+    }
+
+    /// Matches and cascades the style for a lazily-resolved pseudo-element
+    /// (one that isn't in `SelectorImpl::each_eagerly_cascaded_pseudo_element`,
+    /// like `::placeholder` or `::selection`), including its `:visited` style
+    /// if a relevant link was found while matching.
+    ///
+    /// This is the entry point the `resolve_style`/probe path used from
+    /// `ThreadSafeLayoutElement` should call into to resolve a lazy pseudo,
+    /// instead of only cascading the unvisited style the way it used to:
+    /// without this, selectors like `::selection:visited` (or more commonly,
+    /// `:visited`-dependent declarations nested inside a lazy pseudo's
+    /// ancestor chain) would never apply, because lazily-resolved pseudos
+    /// never got a second, visited-only matching pass.
+    ///
+    /// Unlike `match_pseudos`/`cascade_pseudos`, this doesn't consult or
+    /// update any persistent per-element storage; the resulting
+    /// `ComputedValues` is handed back to the caller to use as it sees fit.
+    ///
+    /// Note that repeated `getComputedStyle` queries on an element inside a
+    /// `display:none` subtree will each force a fresh cascade here, rather
+    /// than hitting a cache. The shape such a cache would need -- an
+    /// element-and-pseudo-keyed map of `Arc<ComputedValues>`, a generation
+    /// counter bumped wholesale on any stylesheet mutation, ancestor
+    /// state/attribute change, or relevant restyle (rather than evicting
+    /// individual entries), exposed on `SharedStyleContext` so both this and
+    /// the non-pseudo element resolution path can share it -- isn't
+    /// something `matching.rs` can add on its own: it doesn't own
+    /// `SharedStyleContext`, and the invalidation hooks live in the restyle
+    /// and stylesheet-mutation code that feeds it, not here.
+    fn match_and_cascade_lazy_pseudo(&self,
+                                     context: &mut StyleContext<Self>,
+                                     pseudo: &PseudoElement,
+                                     primary_style: &Arc<ComputedValues>)
+                                     -> Arc<ComputedValues> {
+        debug_assert!(!pseudo.is_eager());
+
+        let mut inputs = CascadeInputs::default();
+
+        {
+            let stylist = &context.shared.stylist;
+            let guards = &context.shared.guards;
+            let mut selector_flags = SelectorFlagsAccumulator::new();
+            let mut set_selector_flags = |element: &Self, flags: ElementSelectorFlags| {
+                selector_flags.insert(*element, flags);
+            };
+
+            let mut applicable_declarations = ApplicableDeclarationList::new();
+            let bloom_filter = context.thread_local.bloom_filter.filter();
+            let mut matching_context =
+                MatchingContext::new_for_visited(MatchingMode::ForStatelessPseudoElement,
+                                                 Some(bloom_filter),
+                                                 VisitedHandlingMode::AllLinksUnvisited,
+                                                 context.shared.quirks_mode);
+            stylist.push_applicable_declarations(self,
+                                                 Some(pseudo),
+                                                 None,
+                                                 None,
+                                                 AnimationRules(None, None),
+                                                 RuleInclusion::All,
+                                                 &mut applicable_declarations,
+                                                 &mut matching_context,
+                                                 &mut set_selector_flags);
+            let relevant_link_found = matching_context.relevant_link_found;
+
+            if applicable_declarations.is_empty() {
+                selector_flags.flush(self, &mut context.thread_local.selector_flags);
+                return primary_style.clone();
+            }
+
+            let rules = stylist.rule_tree().compute_rule_node(&mut applicable_declarations, guards);
+            inputs.set_rules(VisitedHandlingMode::AllLinksUnvisited, rules);
+
+            // Only bother matching :visited-dependent rules when a relevant
+            // ancestor link was actually found above; otherwise there's
+            // nothing for a visited cascade to differ on, and it would be
+            // wasted work.
+            if relevant_link_found {
+                let mut visited_applicable_declarations = ApplicableDeclarationList::new();
+                let bloom_filter = context.thread_local.bloom_filter.filter();
+                let mut visited_matching_context =
+                    MatchingContext::new_for_visited(MatchingMode::ForStatelessPseudoElement,
+                                                     Some(bloom_filter),
+                                                     VisitedHandlingMode::RelevantLinkVisited,
+                                                     context.shared.quirks_mode);
+                stylist.push_applicable_declarations(self,
+                                                     Some(pseudo),
+                                                     None,
+                                                     None,
+                                                     AnimationRules(None, None),
+                                                     RuleInclusion::All,
+                                                     &mut visited_applicable_declarations,
+                                                     &mut visited_matching_context,
+                                                     &mut set_selector_flags);
+                if !visited_applicable_declarations.is_empty() {
+                    let visited_rules =
+                        stylist.rule_tree().compute_rule_node(&mut visited_applicable_declarations, guards);
+                    inputs.set_rules(VisitedHandlingMode::RelevantLinkVisited, visited_rules);
+                }
+            }
+            selector_flags.flush(self, &mut context.thread_local.selector_flags);
+        }
+
+        let resolver = StyleResolver::new(self.clone());
+        // Reuse the same "do we even have visited rules to cascade" guard
+        // that `cascade_eager_pseudo` relies on, so a lazy pseudo with no
+        // visited-dependent declarations skips the extra cascade entirely.
+        let visited_values_to_insert = if CascadeVisitedMode::Visited.has_rules(&inputs) {
+            Some(resolver.cascade_with_rules(
+                context.shared,
+                &context.thread_local.font_metrics_provider,
+                CascadeVisitedMode::Visited.rules(&inputs),
+                Some(primary_style),
+                CascadeTarget::EagerPseudo,
+                CascadeVisitedMode::Visited,
+                /* parent_info = */ None,
+                None))
+        } else {
+            None
+        };
+
+        resolver.cascade_with_rules(
+            context.shared,
+            &context.thread_local.font_metrics_provider,
+            inputs.rules(),
+            Some(primary_style),
+            CascadeTarget::EagerPseudo,
+            CascadeVisitedMode::Unvisited,
+            /* parent_info = */ None,
+            visited_values_to_insert)
     }
 
 }
 
 impl<E: TElement> MatchMethods for E {}
+
+#[cfg(test)]
+mod tests {
+    use super::same_struct;
+
+    #[test]
+    fn same_struct_is_reference_identity_not_value_equality() {
+        // Two distinct heap allocations with equal contents must compare as
+        // different structs: `inherited_style_may_have_changed` relies on
+        // `same_struct` to tell "same Arc-shared struct" apart from
+        // "coincidentally equal value in a fresh struct", since `cascade()`
+        // always allocates a new struct when it can't prove nothing changed.
+        let a = Box::new(42u32);
+        let b = Box::new(42u32);
+        assert!(!same_struct(&*a, &*b));
+    }
+
+    #[test]
+    fn same_struct_recognizes_the_same_reference() {
+        let a = Box::new(42u32);
+        assert!(same_struct(&*a, &*a));
+    }
+
+    #[test]
+    fn any_flag_differs_ignores_flags_not_in_the_list() {
+        use super::any_flag_differs;
+        use properties::computed_value_flags::*;
+
+        // `HAS_TEXT_DECORATION_LINE` flips, but it isn't in the list we're
+        // asked to check, so it shouldn't be reported as a difference.
+        assert!(!any_flag_differs(
+            ComputedValueFlags::empty(),
+            HAS_TEXT_DECORATION_LINE,
+            &[]));
+    }
+
+    #[test]
+    fn any_flag_differs_detects_a_listed_flag_flipping() {
+        use super::any_flag_differs;
+        use properties::computed_value_flags::*;
+
+        assert!(any_flag_differs(
+            ComputedValueFlags::empty(),
+            HAS_TEXT_DECORATION_LINE,
+            &[HAS_TEXT_DECORATION_LINE]));
+    }
+
+    #[test]
+    fn maps_differ_by_identity_treats_absent_as_distinct_from_present() {
+        use super::maps_differ_by_identity;
+
+        let present = Some(::stylearc::Arc::new(5u32));
+        assert!(maps_differ_by_identity(present.clone(), None));
+        assert!(maps_differ_by_identity(None, present));
+        assert!(!maps_differ_by_identity(None::<::stylearc::Arc<u32>>, None));
+    }
+
+    #[test]
+    fn maps_differ_by_identity_compares_pointers_not_values() {
+        use super::maps_differ_by_identity;
+        use stylearc::Arc;
+
+        let a = Some(Arc::new(5u32));
+        let b = Some(Arc::new(5u32));
+        // Same value, but two distinct allocations: cascade() hands back a
+        // fresh Arc whenever it can't prove the custom properties map is
+        // untouched, so this must count as "may have changed".
+        assert!(maps_differ_by_identity(a.clone(), b));
+        assert!(!maps_differ_by_identity(a.clone(), a));
+    }
+}
@@ -0,0 +1,114 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! An struct to encapsulate all the style fixups that depend on the
+//! element, as opposed to just the `ComputedValues` involved in the
+//! cascade.
+//!
+//! These used to be encoded as `CascadeFlags` computed ahead of time and
+//! threaded all the way down into `cascade()`, which made it easy to lose
+//! track of which flags actually represented real cascading restrictions
+//! versus after-the-fact element-aware adjustments, and meant the element
+//! context was unavailable by the time the fixup needed to run (e.g. during
+//! style reparenting). `StyleAdjuster` instead runs after the cascade has
+//! produced a `ComputedValues`, with the concrete element still at hand.
+
+use dom::TElement;
+use properties::ComputedValues;
+use properties::longhands::display::computed_value as display;
+use stylearc::Arc;
+
+/// A struct that takes care of encapsulating the adjustments to
+/// `ComputedValues` that we need to do given a appropriate `TElement`.
+pub struct StyleAdjuster<E: TElement> {
+    element: E,
+}
+
+impl<E: TElement> StyleAdjuster<E> {
+    /// Trivially construct a new `StyleAdjuster`.
+    pub fn new(element: E) -> Self {
+        StyleAdjuster { element: element }
+    }
+
+    /// Whether `display: contents` is allowed to have an effect for the
+    /// element these values were computed for.
+    ///
+    /// `display: contents` on Native Anonymous Content, or on an eager
+    /// pseudo-element, would make it disappear from the box tree entirely,
+    /// which isn't the intent -- the pseudo exists precisely to generate a
+    /// box, so we reset it back to `inline` instead.
+    fn prohibits_display_contents(&self, is_eager_pseudo: bool) -> bool {
+        self.element.is_native_anonymous() || is_eager_pseudo
+    }
+
+    /// If `display: contents` isn't allowed for this element, make sure it
+    /// doesn't end up with that value.
+    fn adjust_for_prohibited_display_contents(&self,
+                                              is_eager_pseudo: bool,
+                                              style: &mut Arc<ComputedValues>) {
+        if !self.prohibits_display_contents(is_eager_pseudo) {
+            return;
+        }
+
+        if style.get_box().clone_display() != display::T::contents {
+            return;
+        }
+
+        Arc::make_mut(style).mutate_box().set_display(display::T::inline);
+    }
+
+    /// Computes the blockification that the root element, or flex/grid
+    /// items, must go through.
+    ///
+    /// `layout_parent_style` is the style of the closest ancestor that
+    /// generates a box (see `PrivateMatchMethods::layout_parent`), which is
+    /// what we need to consult to know whether we're a flex/grid item: that
+    /// information lives on the parent's `display`, not on our own.
+    ///
+    /// Only the root element itself is blockified as a root: its eager
+    /// pseudo-elements and Native Anonymous Content are not.
+    fn adjust_for_item_and_root_based_display_fixup(&self,
+                                                    is_eager_pseudo: bool,
+                                                    layout_parent_style: Option<&ComputedValues>,
+                                                    style: &mut Arc<ComputedValues>) {
+        if self.element.skip_root_and_item_based_display_fixup() {
+            return;
+        }
+
+        let display = style.get_box().clone_display();
+        if display == display::T::none || display == display::T::contents {
+            // `display: none` and `display: contents` aren't affected by
+            // blockification; there's no box to blockify.
+            return;
+        }
+
+        let is_item = layout_parent_style.map_or(false, |parent| {
+            parent.get_box().clone_display().is_item_container()
+        });
+
+        let is_root = self.element.is_root() &&
+                      !self.prohibits_display_contents(is_eager_pseudo);
+        if (is_root || is_item) && display != display.to_block() {
+            Arc::make_mut(style).mutate_box().set_display(display.to_block());
+        }
+    }
+
+    /// Adjusts the style to account for various fixups that depend on the
+    /// concrete element, rather than on anything in the `ComputedValues`
+    /// itself, now that the cascade for `style` has already happened.
+    ///
+    /// `is_eager_pseudo` should be true if `style` is being computed for an
+    /// eager pseudo-element (`::before`/`::after`), where `display: contents`
+    /// should never have an effect.
+    ///
+    /// `layout_parent_style` is used for the flex/grid item blockification
+    /// fixup; see `adjust_for_item_and_root_based_display_fixup`.
+    pub fn adjust(&self,
+                 is_eager_pseudo: bool,
+                 layout_parent_style: Option<&ComputedValues>,
+                 style: &mut Arc<ComputedValues>) {
+        self.adjust_for_prohibited_display_contents(is_eager_pseudo, style);
+        self.adjust_for_item_and_root_based_display_fixup(is_eager_pseudo, layout_parent_style, style);
+    }
+}
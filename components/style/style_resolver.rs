@@ -0,0 +1,339 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Style resolution for a given element or pseudo-element.
+//!
+//! This module is in charge of knowing how to compute a style for an
+//! element, but is deliberately agnostic about what happens to that style
+//! once it's computed: it doesn't touch `ElementData`, `RestyleData`, or
+//! anything else that makes sense only when styling a document in place.
+//! That bookkeeping lives in `matching`, which drives a `StyleResolver` and
+//! then reacts to the result.
+//!
+//! Keeping the two concerns apart means `StyleResolver` can also be used to
+//! resolve styles for elements that don't have (or don't want to touch)
+//! persistent per-element data, such as `getComputedStyle` queries or
+//! default-style resolution.
+
+#![deny(missing_docs)]
+
+use applicable_declarations::ApplicableDeclarationList;
+use cascade_info::CascadeInfo;
+use context::{CascadeInputs, SharedStyleContext, StyleContext};
+use dom::TElement;
+use font_metrics::FontMetricsProvider;
+use matching::{CascadeTarget, CascadeVisitedMode, ParentElementAndStyle, PrivateMatchMethods};
+use properties::{AnimationRules, CascadeFlags, ComputedValues};
+use properties::{VISITED_DEPENDENT_ONLY, cascade};
+use rule_tree::StrongRuleNode;
+use selectors::matching::{MatchingContext, MatchingMode, VisitedHandlingMode};
+use style_adjuster::StyleAdjuster;
+use stylearc::Arc;
+use stylist::RuleInclusion;
+
+/// Computes styles for a single element or pseudo-element, with no side
+/// effects on the caller's bookkeeping.
+///
+/// A `StyleResolver` borrows nothing that's specific to the currently
+/// committed style for `element`, so it's safe to run it speculatively, or
+/// more than once, and only act on the result afterwards.
+pub struct StyleResolver<E: TElement> {
+    element: E,
+}
+
+impl<E: TElement> StyleResolver<E> {
+    /// Creates a new `StyleResolver` for the given element.
+    pub fn new(element: E) -> Self {
+        StyleResolver { element: element }
+    }
+
+    /// A common path for the cascade used by both primary elements and eager
+    /// pseudo-elements after collecting the appropriate rules to use.
+    ///
+    /// `primary_style` is expected to be Some for eager pseudo-elements.
+    ///
+    /// `parent_info` is our style parent and its primary style, if
+    /// it's already been computed.
+    pub fn cascade_with_rules(&self,
+                              shared_context: &SharedStyleContext,
+                              font_metrics_provider: &FontMetricsProvider,
+                              rule_node: &StrongRuleNode,
+                              primary_style: Option<&Arc<ComputedValues>>,
+                              cascade_target: CascadeTarget,
+                              cascade_visited: CascadeVisitedMode,
+                              parent_info: Option<&ParentElementAndStyle<E>>,
+                              visited_values_to_insert: Option<Arc<ComputedValues>>)
+                              -> Arc<ComputedValues> {
+        let element = self.element;
+        let mut cascade_info = CascadeInfo::new();
+        let mut cascade_flags = CascadeFlags::empty();
+        if cascade_visited.visited_dependent_only() {
+            cascade_flags.insert(VISITED_DEPENDENT_ONLY);
+        }
+        // NB: Root- and item-based display fixups, and prohibiting
+        // `display: contents`, used to be threaded down into `cascade()` as
+        // flags computed purely from element predicates. Those aren't
+        // genuine restrictions on what gets cascaded, so they're now applied
+        // by `StyleAdjuster` below, once we actually have a `ComputedValues`
+        // and the concrete element to adjust it for.
+
+        // Grab the inherited values.
+        let parent_el;
+        let element_and_style; // So parent_el and style_to_inherit_from are known live.
+        let style_to_inherit_from = match cascade_target {
+            CascadeTarget::Normal => {
+                let info = match parent_info {
+                    Some(element_and_style) => element_and_style,
+                    None => {
+                        element_and_style = element.get_inherited_style_and_parent();
+                        &element_and_style
+                    }
+                };
+                parent_el = info.element;
+                info.style.as_ref().map(|s| cascade_visited.values(s))
+            }
+            CascadeTarget::EagerPseudo => {
+                parent_el = Some(element);
+                Some(cascade_visited.values(primary_style.unwrap()))
+            }
+        };
+
+        let mut layout_parent_el = parent_el.clone();
+        let layout_parent_data;
+        let mut layout_parent_style = style_to_inherit_from;
+        if style_to_inherit_from.map_or(false, |s| s.is_display_contents()) {
+            layout_parent_el = Some(layout_parent_el.unwrap().layout_parent());
+            layout_parent_data = layout_parent_el.as_ref().unwrap().borrow_data().unwrap();
+            layout_parent_style = Some(cascade_visited.values(layout_parent_data.styles.primary()));
+        }
+
+        let style_to_inherit_from = style_to_inherit_from.map(|x| &**x);
+        let layout_parent_style = layout_parent_style.map(|x| &**x);
+
+        // Propagate the "can be fragmented" bit. It would be nice to
+        // encapsulate this better.
+        //
+        // Note that this is technically not needed for pseudos since we already
+        // do that when we resolve the non-pseudo style, but it doesn't hurt
+        // anyway.
+        //
+        // TODO(emilio): This is servo-only, move somewhere else?
+        if let Some(ref p) = layout_parent_style {
+            let can_be_fragmented =
+                p.is_multicol() ||
+                layout_parent_el.as_ref().unwrap().as_node().can_be_fragmented();
+            unsafe { element.as_node().set_can_be_fragmented(can_be_fragmented); }
+        }
+
+        // Invoke the cascade algorithm.
+        let mut values =
+            Arc::new(cascade(shared_context.stylist.device(),
+                             rule_node,
+                             &shared_context.guards,
+                             style_to_inherit_from,
+                             layout_parent_style,
+                             visited_values_to_insert,
+                             Some(&mut cascade_info),
+                             font_metrics_provider,
+                             cascade_flags,
+                             shared_context.quirks_mode));
+
+        cascade_info.finish(&element.as_node());
+
+        // The matched rule node only needs to live in `CascadeInputs` for
+        // the duration of matching and cascading: `cascade()` stashes the
+        // rule node it was given on the resulting `ComputedValues` (the same
+        // way visited values are reachable from the regular ones), so that's
+        // the only copy callers need to keep around afterwards. Permanent
+        // per-element storage can and should read it from there rather than
+        // holding a second `StrongRuleNode` of its own -- this is already
+        // how `get_base_style` and `get_after_change_style` in `matching.rs`
+        // recover a style's rule node, via `ComputedValues::rules()`, long
+        // after the `CascadeInputs` that fed its cascade is gone.
+        debug_assert!(*values.rules() == *rule_node,
+                      "cascade() should have recorded the rule node it cascaded with");
+
+        // Perform the element-aware display fixups (blockification of the
+        // root element and flex/grid items, and prohibiting
+        // `display: contents` for NAC and eager pseudos) now that we have
+        // both the concrete element and the freshly-cascaded values at hand.
+        StyleAdjuster::new(element)
+            .adjust(cascade_target == CascadeTarget::EagerPseudo,
+                   layout_parent_style,
+                   &mut values);
+
+        values
+    }
+
+    /// Resolves the style of the primary element or an eager pseudo-element,
+    /// given the already-matched rule nodes for it.
+    ///
+    /// `primary_style` is expected to be Some for eager pseudo-elements.
+    ///
+    /// `parent_info` is our style parent and its primary style, if
+    /// it's already been computed.
+    ///
+    /// This returns the resolved `ComputedValues` only; it never writes to
+    /// `ElementData`, `RestyleData`, or `context.thread_local`. It does read
+    /// the parent's `ElementData` (for an element-backed eager pseudo, and
+    /// for the inherited and layout parent styles) and the font metrics
+    /// provider in `context.thread_local`.
+    pub fn cascade_style_and_visited(&self,
+                                     context: &StyleContext<E>,
+                                     primary_style: Option<&Arc<ComputedValues>>,
+                                     primary_inputs: &CascadeInputs,
+                                     eager_pseudo_inputs: Option<&CascadeInputs>,
+                                     parent_info: Option<&ParentElementAndStyle<E>>,
+                                     cascade_visited: CascadeVisitedMode)
+                                     -> Arc<ComputedValues> {
+        let element = self.element;
+        if let Some(pseudo) = element.implemented_pseudo_element() {
+            debug_assert!(eager_pseudo_inputs.is_none());
+
+            // This is an element-backed pseudo, just grab the styles from the
+            // parent if it's eager, and recascade otherwise.
+            //
+            // We also recascade if the eager pseudo-style has any animation
+            // rules, because we don't cascade those during the eager traversal.
+            //
+            // We could make that a bit better if the complexity cost is not too
+            // big, but given further restyles are posted directly to
+            // pseudo-elements, it doesn't seem worth the effort at a glance.
+            //
+            // For the same reason as described in match_primary, if we are
+            // computing default styles, we aren't guaranteed the parent
+            // will have eagerly computed our styles, so we just handled it
+            // below like a lazy pseudo.
+            let only_default_rules = context.shared.traversal_flags.for_default_styles();
+            if pseudo.is_eager() && !only_default_rules {
+                debug_assert!(pseudo.is_before_or_after());
+                let parent = element.parent_element().unwrap();
+                if !parent.may_have_animations() ||
+                   primary_inputs.rules().get_animation_rules().is_empty() {
+                    let parent_data = parent.borrow_data().unwrap();
+                    let pseudo_style =
+                        parent_data.styles.pseudos.get(&pseudo).unwrap();
+                    let values = cascade_visited.values(pseudo_style);
+                    return values.clone()
+                }
+            }
+        }
+
+        // Find possible visited computed styles to insert within the regular
+        // computed values we are about to create.
+        let visited_values_to_insert = if cascade_visited.visited_values_for_insertion() {
+            match eager_pseudo_inputs {
+                Some(ref s) => s.clone_visited_values(),
+                None => primary_inputs.clone_visited_values(),
+            }
+        } else {
+            None
+        };
+
+        // Grab the rule node.
+        let inputs = eager_pseudo_inputs.unwrap_or(primary_inputs);
+        // We'd really like to take the rules here to avoid refcount traffic,
+        // but animation's usage of `apply_declarations` make this tricky.
+        // See bug 1375525.
+        let rule_node = cascade_visited.rules(inputs);
+        let cascade_target = if eager_pseudo_inputs.is_some() {
+            CascadeTarget::EagerPseudo
+        } else {
+            CascadeTarget::Normal
+        };
+
+        self.cascade_with_rules(context.shared,
+                                &context.thread_local.font_metrics_provider,
+                                rule_node,
+                                primary_style,
+                                cascade_target,
+                                cascade_visited,
+                                parent_info,
+                                visited_values_to_insert)
+    }
+
+    /// Matches and cascades the primary style of `element`, without
+    /// consulting or mutating `ElementData`, `RestyleData`, or any other
+    /// persistent per-element bookkeeping of `element` (selector flags are
+    /// still applied as usual, since they are required for matching to be
+    /// sound and are harmless to set speculatively; the style-sharing cache
+    /// is not touched).
+    ///
+    /// This is the entry point one-off callers that don't have (or don't
+    /// want) a full traversal should use: `getComputedStyle` queries on
+    /// elements outside the flat tree, default-style probes, and similar.
+    /// Regular styling continues to go through `MatchMethods::match_primary`
+    /// and `cascade_primary`, which additionally update `ElementData`
+    /// (committed values, restyle damage, animation bookkeeping) the way a
+    /// real traversal needs.
+    pub fn resolve_primary_style_standalone(&self,
+                                            context: &mut StyleContext<E>)
+                                            -> Arc<ComputedValues> {
+        let only_default_rules = context.shared.traversal_flags.for_default_styles();
+        let rule_inclusion = if only_default_rules {
+            RuleInclusion::DefaultOnly
+        } else {
+            RuleInclusion::All
+        };
+
+        self.resolve_style_with_inclusion(context, rule_inclusion)
+    }
+
+    /// Matches and cascades the primary style of `element` as in
+    /// `resolve_primary_style_standalone`, but limits the rules considered
+    /// to those up to and including `rule_inclusion`'s cascade origin,
+    /// rather than always matching everything (or only UA/default rules).
+    ///
+    /// This is what powers devtools-style queries like "what would this
+    /// element look like with only UA styles" or "with author styles
+    /// removed", and gives embedders a way to diff author contributions
+    /// against the default cascade, without needing a second, bespoke
+    /// matching codepath per origin.
+    pub fn resolve_style_with_inclusion(&self,
+                                        context: &mut StyleContext<E>,
+                                        rule_inclusion: RuleInclusion)
+                                        -> Arc<ComputedValues> {
+        let element = self.element;
+
+        let mut applicable_declarations = ApplicableDeclarationList::new();
+        let stylist = &context.shared.stylist;
+
+        let map = &mut context.thread_local.selector_flags;
+        let mut set_selector_flags = |el: &E, flags| {
+            element.apply_selector_flags(map, el, flags);
+        };
+
+        let bloom_filter = context.thread_local.bloom_filter.filter();
+        let mut matching_context =
+            MatchingContext::new_for_visited(MatchingMode::Normal,
+                                             Some(bloom_filter),
+                                             VisitedHandlingMode::AllLinksUnvisited,
+                                             context.shared.quirks_mode);
+
+        stylist.push_applicable_declarations(element,
+                                             element.implemented_pseudo_element().as_ref(),
+                                             element.style_attribute(),
+                                             None,
+                                             AnimationRules(None, None),
+                                             rule_inclusion,
+                                             &mut applicable_declarations,
+                                             &mut matching_context,
+                                             &mut set_selector_flags);
+
+        let rule_node = stylist.rule_tree().compute_rule_node(
+            &mut applicable_declarations,
+            &context.shared.guards
+        );
+
+        let mut inputs = CascadeInputs::default();
+        inputs.set_rules(VisitedHandlingMode::AllLinksUnvisited, rule_node);
+
+        self.cascade_style_and_visited(context,
+                                       None,
+                                       &inputs,
+                                       None,
+                                       /* parent_info = */ None,
+                                       CascadeVisitedMode::Unvisited)
+    }
+}